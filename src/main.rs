@@ -1,17 +1,27 @@
 extern crate clap;
 extern crate threadpool;
 extern crate memmap;
+extern crate grep;
+extern crate ignore;
+extern crate ctrlc;
 
 use clap::{Arg, App, SubCommand};
 use std::fs::{self, File};
 use std::sync::mpsc::{Sender, Receiver, SendError};
 use std::sync::mpsc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
 use std::io;
 use std::io::prelude::*;
 use std::path::PathBuf;
 use self::memmap::Mmap;
 use self::threadpool::ThreadPool;
+use self::grep::regex::RegexMatcher;
+use self::grep::matcher::Matcher;
+use self::ignore::WalkBuilder;
+use self::ignore::overrides::OverrideBuilder;
+use self::ignore::types::TypesBuilder;
 use std::time::{Duration, SystemTime};
 use std::io::{Write, Stdout};
 
@@ -27,13 +37,14 @@ fn main() {
         .version("0.0.1")
         .author("Devyn Goetsch")
         .about("reads stuff")
-        .arg(Arg::with_name("path")
-            .help("path to search")
-            .required(true)
-            .index(1))
         .arg(Arg::with_name("query")
             .help("string to query for")
             .required(true)
+            .index(1))
+        .arg(Arg::with_name("path")
+            .help("one or more files or directories to search")
+            .required(true)
+            .multiple(true)
             .index(2))
         .arg(Arg::with_name("debug_file")
             .long("debug_file")
@@ -43,19 +54,131 @@ fn main() {
             .long("log_level")
             .help("log level to print to standaord out, off if absent")
             .takes_value(true))
+        .arg(Arg::with_name("glob")
+            .long("glob")
+            .short("g")
+            .help("include/exclude files matching glob, repeatable, prefix with ! to negate")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("type")
+            .long("type")
+            .short("t")
+            .help("only search files of the named type group (e.g. rust, py, md), repeatable")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("hidden")
+            .long("hidden")
+            .help("search hidden files and directories"))
+        .arg(Arg::with_name("no_ignore")
+            .long("no-ignore")
+            .help("do not respect .gitignore/.ignore files"))
+        .arg(Arg::with_name("before_context")
+            .long("before-context")
+            .short("B")
+            .help("print N lines of context before each match")
+            .takes_value(true))
+        .arg(Arg::with_name("after_context")
+            .long("after-context")
+            .short("A")
+            .help("print N lines of context after each match")
+            .takes_value(true))
+        .arg(Arg::with_name("context")
+            .long("context")
+            .short("C")
+            .help("print N lines of context before and after each match")
+            .takes_value(true))
+        .arg(Arg::with_name("format")
+            .long("format")
+            .help("output format: text (default) or json for newline-delimited JSON")
+            .takes_value(true))
+        .arg(Arg::with_name("timeout")
+            .long("timeout")
+            .help("cancel the search after N seconds")
+            .takes_value(true))
+        .arg(Arg::with_name("files_with_matches")
+            .long("files-with-matches")
+            .short("l")
+            .help("print only the path of each file with a match"))
+        .arg(Arg::with_name("count")
+            .long("count")
+            .short("c")
+            .help("print only a count of matching lines per file"))
+        .arg(Arg::with_name("invert_match")
+            .long("invert-match")
+            .short("v")
+            .help("report lines that do not match"))
+        .arg(Arg::with_name("max_depth")
+            .long("max-depth")
+            .help("do not descend more than N directories deep")
+            .takes_value(true))
+        .arg(Arg::with_name("min_depth")
+            .long("min-depth")
+            .help("do not report entries shallower than N directories deep")
+            .takes_value(true))
+        .arg(Arg::with_name("follow")
+            .long("follow")
+            .help("follow symbolic links while walking directories"))
         .get_matches();
     let (tx, rx): (Sender<(PathBuf, String)>, Receiver<(PathBuf, String)>) = mpsc::channel();
     let (result_sender, result_receiver): (Sender<SearchResult>, Receiver<SearchResult>) = mpsc::channel();
     let num_workers = 16;
 
-    let search = Search::new(tx, result_sender, num_workers);
-   
+    let walk = WalkConfig {
+        globs: matches.values_of("glob").map(|vs| vs.map(|v| v.to_string()).collect()).unwrap_or_else(Vec::new),
+        types: matches.values_of("type").map(|vs| vs.map(|v| v.to_string()).collect()).unwrap_or_else(Vec::new),
+        hidden: matches.is_present("hidden"),
+        no_ignore: matches.is_present("no_ignore"),
+        max_depth: matches.value_of("max_depth").and_then(|v| v.parse::<usize>().ok()),
+        min_depth: matches.value_of("min_depth").and_then(|v| v.parse::<usize>().ok()).unwrap_or(0),
+        follow: matches.is_present("follow"),
+    };
+
+    let both = matches.value_of("context").and_then(|v| v.parse::<usize>().ok());
+    let context = ContextConfig {
+        before: both.or_else(|| matches.value_of("before_context").and_then(|v| v.parse::<usize>().ok())).unwrap_or(0),
+        after: both.or_else(|| matches.value_of("after_context").and_then(|v| v.parse::<usize>().ok())).unwrap_or(0),
+    };
+
+    let format = match matches.value_of("format").map(|f| f.to_lowercase()) {
+        Some(ref f) if f == "json" => OutputFormat::Json,
+        _ => OutputFormat::Text,
+    };
+
+    let mode = ModeConfig {
+        files_with_matches: matches.is_present("files_with_matches"),
+        count: matches.is_present("count"),
+        invert: matches.is_present("invert_match"),
+    };
+
+    let search = Search::new(tx, result_sender, num_workers, walk, context, format, mode);
+
     init_logger(matches.value_of("log_level"), matches.value_of("debug_file"));
 
-    matches.value_of("path")
-        .and_then(|path| matches.value_of("query").map(|query| Ok((PathBuf::from(path), query.to_string()))))
+    // Ctrl-C and --timeout both trip the same shared cancel flag so outstanding
+    // jobs drain cleanly instead of the process being killed mid-write.
+    let cancel = search.cancel_handle();
+    {
+        let cancel = cancel.clone();
+        ctrlc::set_handler(move || cancel.store(true, Ordering::SeqCst))
+            .err().iter()
+            .for_each(|err| error!("Could not install SIGINT handler: {:?}", err));
+    }
+    matches.value_of("timeout")
+        .and_then(|secs| secs.parse::<u64>().ok())
+        .map(|secs| {
+            let cancel = cancel.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_secs(secs));
+                cancel.store(true, Ordering::SeqCst);
+            });
+        });
+
+    matches.value_of("query")
+        .and_then(|query| matches.values_of("path").map(|paths| Ok((query.to_string(), paths.map(PathBuf::from).collect::<Vec<PathBuf>>()))))
         .unwrap_or( Err(AppError::Startup("Missing Required Params".to_string())))
-        .and_then(|(path, query)| search.search(path, query))
+        .map(|(query, paths)| paths.into_iter().for_each(|path| search.seed(path, query.clone())))
         .map(|()| Search::process_queries(search, rx, result_receiver))
         .err().iter()
         .for_each(|err| {
@@ -101,16 +224,70 @@ struct Search {
     tx: Sender<(PathBuf, String)>,
     result_sender: Sender<SearchResult>,
     thread_pool: ThreadPool,
+    walk: WalkConfig,
+    context: ContextConfig,
+    format: OutputFormat,
+    cancel: Arc<AtomicBool>,
+    mode: ModeConfig,
+}
+
+/// Number of surrounding lines to include around each match.
+#[derive(Debug, Clone)]
+struct ContextConfig {
+    before: usize,
+    after: usize,
+}
+
+/// Filtering options that govern how `search_dir` traverses a directory tree.
+#[derive(Debug, Clone)]
+struct WalkConfig {
+    globs: Vec<String>,
+    types: Vec<String>,
+    hidden: bool,
+    no_ignore: bool,
+    max_depth: Option<usize>,
+    min_depth: usize,
+    follow: bool,
 }
 
 #[derive(Debug, Clone)]
 enum SearchResult {
-    Contents(String, usize),
+    Contents(Match),
+    Context(String, u64, String),
+    Count(String, usize),
     File(String),
     Dir(String),
     Error(AppError, (PathBuf, String))
 }
 
+/// Aggregate output modes that change what a per-file search reports.
+#[derive(Debug, Clone)]
+struct ModeConfig {
+    files_with_matches: bool,
+    count: bool,
+    invert: bool,
+}
+
+/// A single content match: where it landed (line/column/absolute offset), the
+/// full line it sits on, the matched substring and every submatch span (start
+/// and end byte offsets within the line) for lines with more than one hit.
+#[derive(Debug, Clone)]
+struct Match {
+    path: String,
+    line: u64,
+    offset: usize,
+    column: usize,
+    line_text: String,
+    matched: String,
+    submatches: Vec<(usize, usize)>,
+}
+
+#[derive(Debug, Clone)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
 
 fn lift<T>(results: Vec<Result<T, AppError>>) -> Result<Vec<T>, AppError> {
     let (ok, err): (Vec<Result<T, AppError>>, Vec<Result<T, AppError>>) = results
@@ -124,19 +301,252 @@ fn lift<T>(results: Vec<Result<T, AppError>>) -> Result<Vec<T>, AppError> {
 }
 
 
+/// Characters that, if present in a query, force the full regular-expression
+/// engine. Queries free of these stay on the cheap literal byte scan.
+fn is_regex_query(query: &str) -> bool {
+    query.chars().any(|c| ".^$*+?()[]{}|\\".contains(c))
+}
+
+/// Escape a string for embedding in a JSON string literal.
+fn json_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Render a single result as one NDJSON record (no trailing newline).
+fn render_json(result: &SearchResult) -> String {
+    match result {
+        SearchResult::Contents(m) => {
+            let subs = m.submatches.iter()
+                .map(|(start, end)| format!("{{\"start\":{},\"end\":{}}}", start, end))
+                .collect::<Vec<String>>()
+                .join(",");
+            format!("{{\"type\":\"match\",\"path\":\"{}\",\"line\":{},\"offset\":{},\"column\":{},\"matched\":\"{}\",\"submatches\":[{}]}}",
+                json_escape(&m.path), m.line, m.offset, m.column, json_escape(&m.matched), subs)
+        }
+        SearchResult::Context(path, line, text) =>
+            format!("{{\"type\":\"context\",\"path\":\"{}\",\"line\":{},\"text\":\"{}\"}}", json_escape(path), line, json_escape(text)),
+        SearchResult::Count(path, count) =>
+            format!("{{\"type\":\"count\",\"path\":\"{}\",\"count\":{}}}", json_escape(path), count),
+        SearchResult::File(path) =>
+            format!("{{\"type\":\"file\",\"path\":\"{}\"}}", json_escape(path)),
+        SearchResult::Dir(path) =>
+            format!("{{\"type\":\"dir\",\"path\":\"{}\"}}", json_escape(path)),
+        SearchResult::Error(error, (path, query)) =>
+            format!("{{\"type\":\"error\",\"path\":\"{}\",\"query\":\"{}\",\"message\":\"{}\"}}",
+                json_escape(&path.to_string_lossy()), json_escape(query), json_escape(&format!("{:?}", error))),
+    }
+}
+
+fn emit(sender: &Sender<SearchResult>, result: SearchResult) {
+    sender.send(result.clone())
+        .err().iter()
+        .for_each(|err| error!("Could not handle {:?} because {:?}", result, err));
+}
+
+/// The matching engine for a single query: a literal substring scan when the
+/// query is free of regex metacharacters, otherwise the `grep::regex` matcher.
+/// Both report the same thing — the submatch spans on a given line — so the
+/// scan/emit path downstream is identical regardless of which one is in play.
+///
+/// We deliberately drive the matcher line-by-line from `scan` rather than
+/// handing the buffer to `grep::searcher::Searcher` with a `Sink`. The literal
+/// engine has no `Matcher` impl, and routing only the regex case through
+/// `Searcher` left the two engines emitting subtly different context and column
+/// output; funnelling both through one `submatches` call is what keeps identical
+/// flags producing identical output. Our own line walk also owns the coalesced
+/// context window and submatch columns the NDJSON format needs, which the
+/// `Sink` callbacks do not surface directly.
+enum LineMatcher {
+    Literal(Vec<u8>),
+    Regex(RegexMatcher),
+}
+
+impl LineMatcher {
+    fn new(query: &str) -> Result<LineMatcher, AppError> {
+        if is_regex_query(query) {
+            RegexMatcher::new(query)
+                .map(LineMatcher::Regex)
+                .map_err(|e| AppError::FileIO(e.to_string()))
+        } else {
+            Ok(LineMatcher::Literal(query.as_bytes().to_vec()))
+        }
+    }
+
+    /// Every match on `line`, as (start, end) byte spans within the line.
+    fn submatches(&self, line: &[u8]) -> Vec<(usize, usize)> {
+        match *self {
+            LineMatcher::Literal(ref query) => literal_submatches(line, query),
+            LineMatcher::Regex(ref matcher) => {
+                let mut spans = Vec::new();
+                matcher.find_iter(line, |m| { spans.push((m.start(), m.end())); true })
+                    .err().iter()
+                    .for_each(|err| error!("Could not enumerate submatches: {:?}", err));
+                spans
+            }
+        }
+    }
+}
+
+/// Every non-overlapping occurrence of `query` in `line`, as (start, end) byte
+/// spans within the line.
+fn literal_submatches(line: &[u8], query: &[u8]) -> Vec<(usize, usize)> {
+    let mut spans = Vec::new();
+    if query.is_empty() {
+        return spans;
+    }
+    let mut start = 0usize;
+    while start + query.len() <= line.len() {
+        match line[start..].windows(query.len()).position(|window| window == query) {
+            Some(rel) => {
+                let at = start + rel;
+                spans.push((at, at + query.len()));
+                start = at + query.len();
+            }
+            None => break,
+        }
+    }
+    spans
+}
+
+/// Walk the mmap one line at a time, keeping only a bounded ring buffer of the
+/// last `before` lines rather than the whole file. Context windows are
+/// coalesced: a line is emitted at most once, and a line that is itself a match
+/// is never reprinted as a neighbour's context (tracked via `last_emitted`).
+/// Both the literal and regex engines flow through here so identical flags
+/// produce identical output.
+fn scan(bytes: &[u8], matcher: &LineMatcher, path: &str, sender: &Sender<SearchResult>, context: &ContextConfig, cancel: &Arc<AtomicBool>, mode: &ModeConfig) {
+    let aggregate = mode.files_with_matches || mode.count;
+    let mut ring: std::collections::VecDeque<(u64, &[u8])> = std::collections::VecDeque::with_capacity(context.before + 1);
+    let mut after_remaining = 0usize;
+    let mut last_emitted: Option<u64> = None;
+    let mut count = 0usize;
+    let mut offset = 0usize;
+    let mut line_number = 0u64;
+
+    // An empty input has no lines at all. `split` on a newline-terminated file
+    // yields a phantom empty segment after the final `\n`; drop it so a trailing
+    // newline does not manufacture a spurious empty last line (which `-v` would
+    // count/report and `-A` would print as context).
+    if bytes.is_empty() {
+        return;
+    }
+    let body = match *bytes.last().unwrap() {
+        b'\n' => &bytes[..bytes.len() - 1],
+        _ => bytes,
+    };
+
+    for line in body.split(|b| *b == b'\n') {
+        if cancel.load(Ordering::SeqCst) {
+            break;
+        }
+        line_number += 1;
+        let line_start = offset;
+        offset += line.len() + 1;
+
+        let submatches = matcher.submatches(line);
+        let is_match = if mode.invert { submatches.is_empty() } else { !submatches.is_empty() };
+
+        if is_match {
+            count += 1;
+            if mode.files_with_matches {
+                emit(sender, SearchResult::File(path.to_string()));
+                return;
+            }
+            if mode.count {
+                continue;
+            }
+
+            // Flush the before-context, skipping any line already emitted.
+            while let Some((cline, ctext)) = ring.pop_front() {
+                if last_emitted.map_or(true, |last| cline > last) {
+                    emit(sender, SearchResult::Context(path.to_string(), cline, String::from_utf8_lossy(ctext).to_string()));
+                    last_emitted = Some(cline);
+                }
+            }
+
+            let column = submatches.first().map(|(start, _)| *start).unwrap_or(0);
+            let matched = submatches.first()
+                .map(|(start, end)| String::from_utf8_lossy(&line[*start..(*end).min(line.len())]).to_string())
+                .unwrap_or_default();
+            emit(sender, SearchResult::Contents(Match {
+                path: path.to_string(),
+                line: line_number,
+                offset: line_start + column,
+                column: column,
+                line_text: String::from_utf8_lossy(line).to_string(),
+                matched: matched,
+                submatches: submatches,
+            }));
+            last_emitted = Some(line_number);
+            after_remaining = context.after;
+        } else if !aggregate && after_remaining > 0 {
+            if last_emitted.map_or(true, |last| line_number > last) {
+                emit(sender, SearchResult::Context(path.to_string(), line_number, String::from_utf8_lossy(line).to_string()));
+                last_emitted = Some(line_number);
+            }
+            after_remaining -= 1;
+        } else if !aggregate && context.before > 0 {
+            ring.push_back((line_number, line));
+            if ring.len() > context.before {
+                ring.pop_front();
+            }
+        }
+    }
+
+    // A recursive walk visits every file; reporting `path:0` for the ones that
+    // never matched is noise, so only surface non-zero counts.
+    if mode.count && count > 0 {
+        emit(sender, SearchResult::Count(path.to_string(), count));
+    }
+}
+
 impl Search {
-    fn new(tx: Sender<(PathBuf, String)>, result_sender: Sender<SearchResult>, num_workers: usize) -> Search {
+    fn new(tx: Sender<(PathBuf, String)>, result_sender: Sender<SearchResult>, num_workers: usize, walk: WalkConfig, context: ContextConfig, format: OutputFormat, mode: ModeConfig) -> Search {
         Search{
-            tx: tx, 
-            result_sender: result_sender, 
+            tx: tx,
+            result_sender: result_sender,
             thread_pool: ThreadPool::new(num_workers),
+            walk: walk,
+            context: context,
+            format: format,
+            cancel: Arc::new(AtomicBool::new(false)),
+            mode: mode,
         }
     }
 
+    /// Shared handle to the cancel flag, for the SIGINT/timeout triggers.
+    fn cancel_handle(&self) -> Arc<AtomicBool> {
+        self.cancel.clone()
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancel.load(Ordering::SeqCst)
+    }
+
+    /// Seed a search root into the work queue.
+    fn seed(&self, path: PathBuf, query: String) {
+        self.tx.send((path, query))
+            .err().iter()
+            .for_each(|err| error!("Could not seed search root: {:?}", err));
+    }
+
     fn process_queries(search: Search, rx: Receiver<(PathBuf, String)>, result_receiver: Receiver<SearchResult>) {
+        let format = search.format.clone();
         let query_thread = thread::spawn(move || {
             let mut next_query = rx.try_recv();
-            while next_query.is_ok() {
+            while next_query.is_ok() && !search.is_cancelled() {
                 next_query
                     .map(|(path, query)| search.search(path, query));
                 next_query = rx.try_recv();
@@ -149,15 +559,18 @@ impl Search {
             let mut writer = std::io::stdout();
             let mut next_result = result_receiver.recv();
             while next_result.is_ok(){
-                next_result.map(|search_result| {
-                    match search_result {
-                        SearchResult::Contents(path, pos) => writer.write_fmt(format_args!("{}::{}\n", path, pos)),
+                next_result.map(|search_result| match format {
+                    OutputFormat::Json => writer.write_fmt(format_args!("{}\n", render_json(&search_result))),
+                    OutputFormat::Text => match search_result {
+                        SearchResult::Contents(m) => writer.write_fmt(format_args!("{}:{}:{}: {}\n", m.path, m.line, m.column, m.line_text)),
+                        SearchResult::Context(path, line, text) => writer.write_fmt(format_args!("{}-{}- {}\n", path, line, text)),
+                        SearchResult::Count(path, count) => writer.write_fmt(format_args!("{}:{}\n", path, count)),
                         SearchResult::File(path) => writer.write_fmt(format_args!("{}\n", path)),
                         SearchResult::Dir(path) => writer.write_fmt(format_args!("{}\n", path)),
                         SearchResult::Error(error, (path, query)) => {
                             error!("Error while searching {:?} for {}: {:?}", path, query, error);
                             Ok(())
-                        }  
+                        }
                     }
                 })
                 .err().into_iter()
@@ -193,62 +606,86 @@ impl Search {
             .filter(|p| p.to_string().ends_with(query.as_str()))
             .map(|p| self.result_sender.send(SearchResult::Dir(p.to_string())));
 
-        fs::read_dir(path.clone())
-            .map_err(|e| AppError::FileIO(e.to_string()))
-            .map(|entries|
-                entries
-                    .into_iter()
-                    .map(|r| { r
-                        .map_err(|e| AppError::FileIO(e.to_string()))
-                        .and_then(|entry| { self.tx.send((entry.path(), query.clone())).map_err(|e| AppError::Send(e.to_string()) )})
-                    })
-                    .collect::<Vec<Result<(), AppError>>>())
+        // Depth bounds are enforced directly on the walk: `max_depth` via the
+        // builder (see `build_walk`) and `min_depth` by the filter below.
+        let min_depth = self.walk.min_depth;
+        self.build_walk(&path)
+            .map(|walk| walk
+                .take_while(|_| !self.is_cancelled())
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.path() != path.as_path())
+                .filter(|entry| entry.depth() >= min_depth)
+                .filter(|entry| entry.file_type().map(|ft| ft.is_file()).unwrap_or(false))
+                .map(|entry| self.tx.send((entry.path().to_path_buf(), query.clone())).map_err(|e| AppError::Send(e.to_string())))
+                .collect::<Vec<Result<(), AppError>>>())
             .and_then(lift)
             .map(|_| ())
     }
 
+    /// Assemble an `ignore::Walk` that prunes ignored subtrees, hidden files and
+    /// anything outside the requested globs or type groups before the entries
+    /// ever reach the content-search pool.
+    fn build_walk(&self, path: &PathBuf) -> Result<ignore::Walk, AppError> {
+        let mut builder = WalkBuilder::new(path);
+        builder
+            .hidden(!self.walk.hidden)
+            .git_ignore(!self.walk.no_ignore)
+            .git_global(!self.walk.no_ignore)
+            .git_exclude(!self.walk.no_ignore)
+            .ignore(!self.walk.no_ignore)
+            .parents(!self.walk.no_ignore)
+            .follow_links(self.walk.follow)
+            .max_depth(self.walk.max_depth);
+
+        let with_overrides = if self.walk.globs.is_empty() {
+            Ok(())
+        } else {
+            let mut overrides = OverrideBuilder::new(path);
+            lift(self.walk.globs.iter()
+                    .map(|g| overrides.add(g).map(|_| ()).map_err(|e| AppError::FileIO(e.to_string())))
+                    .collect())
+                .and_then(|_| overrides.build().map_err(|e| AppError::FileIO(e.to_string())))
+                .map(|ov| { builder.overrides(ov); })
+        };
+
+        with_overrides
+            .and_then(|_| if self.walk.types.is_empty() {
+                Ok(())
+            } else {
+                let mut types = TypesBuilder::new();
+                types.add_defaults();
+                self.walk.types.iter().for_each(|t| { types.select(t); });
+                types.build()
+                    .map_err(|e| AppError::FileIO(e.to_string()))
+                    .map(|t| { builder.types(t); })
+            })
+            .map(|_| builder.build())
+    }
+
     
 
     fn search_file(&self, path: PathBuf, query: String) -> Result<(), AppError> {
         let query_clone = query.clone();
-        let query_bytes = query.clone().into_bytes();
         let sender = self.result_sender.clone();
         let path_clone = path.clone();
+        let context = self.context.clone();
+        let cancel = self.cancel.clone();
+        let mode = self.mode.clone();
 
         self.thread_pool.execute(move || {
-             let result = fs::File::open(path_clone.clone())
-                .and_then(|f|  unsafe { Mmap::map(&f) })
-                .map(|mem_map| {mem_map
-                    .iter()
-                    .fold((0, 0), |(num_matched, pos), file_byte| {
-                        let mut match_count = num_matched;
-                        if match_count >= query_bytes.len() && match_count > 0 {
-                            let result = SearchResult::Contents(path_clone.to_str().unwrap_or("").to_string(), pos);
-                            sender.send(result.clone())
-                                .or_else(|send_err| sender.send(SearchResult::Error(AppError::Send(send_err.to_string()), (path_clone.clone(), query_clone.clone()))))
-                                .err().iter()
-                                .for_each(|err| error!("Could not handle {:?} because {:?}", result, err));
-                            match_count = 0;
-                        }
-                        
-                        (
-                            query_bytes
-                                .get(match_count)
-                                .filter(|query_byte| *file_byte == **query_byte)
-                                .map(|_| match_count + 1)
-                                .unwrap_or(0), 
-                            pos + 1
-                        )
-                    })
-                })
-                .map_err(|e| AppError::FileIO(e.to_string()));
+            let path_str = path_clone.to_str().unwrap_or("").to_string();
+            let result = LineMatcher::new(&query_clone)
+                .and_then(|matcher| fs::File::open(path_clone.clone())
+                    .map_err(|e| AppError::FileIO(e.to_string()))
+                    .and_then(|f| unsafe { Mmap::map(&f) }.map_err(|e| AppError::FileIO(e.to_string())))
+                    .map(|mem_map| scan(&mem_map, &matcher, &path_str, &sender, &context, &cancel, &mode)));
 
             match result {
                 Err(e) => sender.send(SearchResult::Error(e.clone(), (path_clone, query_clone)))
                     .err().iter()
                     .for_each(|err| error!("Could not handle error {:?} because {:?}", e, err)),
                 _ => {}
-            };     
+            };
         });
 
         path.to_str()
@@ -256,4 +693,126 @@ impl Search {
             .map(|p| self.result_sender.send(SearchResult::Dir(p.to_string())).map_err(|err| AppError::Send(err.to_string())))
             .unwrap_or(Ok(()))
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    fn ctx(before: usize, after: usize) -> ContextConfig {
+        ContextConfig { before: before, after: after }
+    }
+
+    fn mode(files_with_matches: bool, count: bool, invert: bool) -> ModeConfig {
+        ModeConfig { files_with_matches: files_with_matches, count: count, invert: invert }
+    }
+
+    /// Run `scan` over `bytes` and collect everything it emits.
+    fn run_scan(bytes: &[u8], query: &str, context: ContextConfig, mode: ModeConfig) -> Vec<SearchResult> {
+        let matcher = LineMatcher::new(query).unwrap();
+        let (tx, rx) = mpsc::channel();
+        let cancel = Arc::new(AtomicBool::new(false));
+        scan(bytes, &matcher, "f", &tx, &context, &cancel, &mode);
+        drop(tx);
+        rx.iter().collect()
+    }
+
+    /// Label each result as (kind, line) for order-sensitive assertions; a
+    /// count result is labelled ("count", total) instead.
+    fn shape(results: &[SearchResult]) -> Vec<(&'static str, u64)> {
+        results.iter().map(|r| match *r {
+            SearchResult::Contents(ref m) => ("match", m.line),
+            SearchResult::Context(_, line, _) => ("context", line),
+            SearchResult::Count(_, count) => ("count", count as u64),
+            SearchResult::File(_) => ("file", 0),
+            SearchResult::Dir(_) => ("dir", 0),
+            SearchResult::Error(_, _) => ("error", 0),
+        }).collect()
+    }
+
+    #[test]
+    fn detects_regex_metacharacters() {
+        assert!(!is_regex_query("hello"));
+        assert!(!is_regex_query("snake_case"));
+        assert!(is_regex_query("a.c"));
+        assert!(is_regex_query("a+"));
+        assert!(is_regex_query("back\\slash"));
+    }
+
+    #[test]
+    fn literal_submatches_are_non_overlapping() {
+        assert_eq!(literal_submatches(b"abcabc", b"abc"), vec![(0, 3), (3, 6)]);
+        assert_eq!(literal_submatches(b"aaa", b"aa"), vec![(0, 2)]);
+        assert!(literal_submatches(b"xyz", b"q").is_empty());
+        assert!(literal_submatches(b"xyz", b"").is_empty());
+    }
+
+    #[test]
+    fn json_escape_handles_control_and_quote() {
+        assert_eq!(json_escape("plain"), "plain");
+        assert_eq!(json_escape("a\"b"), "a\\\"b");
+        assert_eq!(json_escape("a\\b"), "a\\\\b");
+        assert_eq!(json_escape("line\nbreak"), "line\\nbreak");
+        assert_eq!(json_escape("\u{1}"), "\\u0001");
+    }
+
+    #[test]
+    fn scan_reports_all_submatch_spans() {
+        let results = run_scan(b"foo bar foo\n", "foo", ctx(0, 0), mode(false, false, false));
+        assert_eq!(results.len(), 1);
+        match results[0] {
+            SearchResult::Contents(ref m) => {
+                assert_eq!(m.line, 1);
+                assert_eq!(m.column, 0);
+                assert_eq!(m.matched, "foo");
+                assert_eq!(m.submatches, vec![(0, 3), (8, 11)]);
+            }
+            ref other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn scan_coalesces_adjacent_match_context() {
+        // Matches on lines 2 and 3; with -C1 line 2 must not be reprinted as
+        // line 3's before-context, and every line appears at most once.
+        let results = run_scan(b"a\nm\nm\na\na", "m", ctx(1, 1), mode(false, false, false));
+        assert_eq!(shape(&results), vec![("context", 1), ("match", 2), ("match", 3), ("context", 4)]);
+    }
+
+    #[test]
+    fn scan_counts_inverted_lines() {
+        let results = run_scan(b"a\nm\na", "m", ctx(0, 0), mode(false, true, true));
+        assert_eq!(shape(&results), vec![("count", 2)]);
+    }
+
+    #[test]
+    fn scan_trailing_newline_is_not_a_line() {
+        // "a\nm\na\n" is three lines, not four: the segment after the final
+        // newline is not a phantom empty line. Inverted, only the two `a`
+        // lines should count.
+        let results = run_scan(b"a\nm\na\n", "m", ctx(0, 0), mode(false, true, true));
+        assert_eq!(shape(&results), vec![("count", 2)]);
+
+        // The same input, un-inverted, reports a single match on line 2 with
+        // no trailing empty context.
+        let results = run_scan(b"a\nm\na\n", "m", ctx(0, 1), mode(false, false, false));
+        assert_eq!(shape(&results), vec![("match", 2), ("context", 3)]);
+    }
+
+    #[test]
+    fn scan_suppresses_zero_counts() {
+        let results = run_scan(b"a\nb\nc\n", "zzz", ctx(0, 0), mode(false, true, false));
+        assert!(results.is_empty());
+    }
+
+    #[test]
+    fn scan_files_with_matches_reports_path_once() {
+        let results = run_scan(b"x\nfoo\nfoo", "foo", ctx(0, 0), mode(true, false, false));
+        assert_eq!(results.len(), 1);
+        match results[0] {
+            SearchResult::File(ref p) => assert_eq!(p, "f"),
+            ref other => panic!("unexpected result: {:?}", other),
+        }
+    }
 }
\ No newline at end of file